@@ -1,5 +1,9 @@
 use macroquad::prelude::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
 use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, Read, Write};
 
 const VIEW_WIDTH: f32 = 1000.0;
 const VIEW_HEIGHT: f32 = 1000.0;
@@ -13,9 +17,18 @@ const GAS: f32 = 2000.0; // for equation of state
 const REST_DENS: f32 = 300.0; // rest density
 const VISC: f32 = 200.0; // viscosity constant
 const G: Vec2 = Vec2::new(0.0, -10.0); // external (gravitational) forces
-const DT: f32 = 0.0007; // integration timestep
 const BOUND_DAMPING: f32 = -0.5;
-const UPDATES_PER_FRAME: usize = 2;
+
+// Adaptive, CFL-bounded time-stepping (replaces the fixed DT / UPDATES_PER_FRAME).
+const DT_MIN: f32 = 0.0001; // smallest allowed substep
+const DT_MAX: f32 = 0.0007; // largest allowed substep (the old fixed DT)
+const LAMBDA_V: f32 = 0.4; // CFL safety factor on the velocity condition
+const LAMBDA_F: f32 = 0.25; // safety factor on the force condition
+const FRAME_BUDGET: f32 = 0.0014; // simulated time advanced per rendered frame
+
+const CACHE_PATH: &str = "sph.ptc"; // on-disk point cache
+const BAKE_FRAMES: usize = 300; // frames captured by a bake run
+const SCRUB_STEP: f32 = 5.0; // cache frames jumped per scrub keypress
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct Particle {
@@ -38,13 +51,480 @@ impl Particle {
     }
 }
 
+/// Uniform spatial hash grid with cell size `H` (the kernel radius), used to
+/// cut the density/force passes down from all-pairs to the 3x3 block of cells
+/// around each particle. The backing `cells` map lives on `Sim` and is only
+/// cleared (not dropped) between frames so the bucket allocations are reused.
+#[derive(Default)]
+struct Grid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl Grid {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Integer cell a position falls into.
+    fn cell(x: Vec2) -> (i32, i32) {
+        ((x.x / H).floor() as i32, (x.y / H).floor() as i32)
+    }
+
+    /// Rebuild the buckets for the current particle positions, keeping the
+    /// existing allocations around.
+    fn rebuild(&mut self, particles: &[Particle]) {
+        for bucket in self.cells.values_mut() {
+            bucket.clear();
+        }
+        for (i, p) in particles.iter().enumerate() {
+            self.cells.entry(Self::cell(p.x)).or_default().push(i);
+        }
+    }
+
+    /// Indices of every particle in the 3x3 block of cells around `x`.
+    fn neighbors(&self, x: Vec2) -> impl Iterator<Item = usize> + '_ {
+        let (cx, cy) = Self::cell(x);
+        (cx - 1..=cx + 1)
+            .flat_map(move |gx| (cy - 1..=cy + 1).map(move |gy| (gx, gy)))
+            .filter_map(move |key| self.cells.get(&key))
+            .flat_map(|bucket| bucket.iter().copied())
+    }
+}
+
+/// Parameters for the Clavet et al. "particle-based viscoelastic fluid"
+/// solver (double-density relaxation plus linear/quadratic viscosity). These
+/// are the knobs Blender exposes for fluid particles: stiffness, near-stiffness,
+/// rest density and the two viscosity coefficients.
+#[derive(Debug, Clone, Copy)]
+struct FluidParams {
+    k: f32,      // pressure stiffness
+    k_near: f32, // near-pressure stiffness (resists clustering)
+    rho0: f32,   // rest density in the (1-q) metric
+    sigma: f32,  // linear viscosity coefficient
+    beta: f32,   // quadratic viscosity coefficient
+}
+
+impl Default for FluidParams {
+    fn default() -> Self {
+        Self {
+            k: 0.5,
+            k_near: 5.0,
+            rho0: 10.0,
+            sigma: 0.2,
+            beta: 0.2,
+        }
+    }
+}
+
+/// Which pressure solver drives the step. `Sph` is the classic equation-of-state
+/// SPH; `Clavet` is the position-based double-density relaxation scheme.
+#[derive(Debug, Clone, Copy)]
+enum Solver {
+    Sph,
+    Clavet(FluidParams),
+}
+
+/// A single oriented line segment of a collider. The surface normal points to
+/// the segment's left (90° counter-clockwise from `a -> b`); it is re-oriented
+/// against the incoming motion at resolve time so winding order doesn't matter.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    a: Vec2,
+    b: Vec2,
+}
+
+impl Segment {
+    fn normal(&self) -> Vec2 {
+        let d = (self.b - self.a).normalize();
+        Vec2::new(-d.y, d.x)
+    }
+
+    /// Parameter `t` along `p0 -> p1` at which it first crosses this segment,
+    /// if the two segments intersect.
+    fn cross(&self, p0: Vec2, p1: Vec2) -> Option<f32> {
+        let r = p1 - p0;
+        let s = self.b - self.a;
+        let denom = r.perp_dot(s);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.a - p0).perp_dot(s) / denom;
+        let u = (self.a - p0).perp_dot(r) / denom;
+        if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+}
+
+/// A static obstacle: a chain of line segments with a tangential friction
+/// factor. Open polylines and closed polygons are both just segment lists.
+#[derive(Debug, Clone)]
+struct Collider {
+    segments: Vec<Segment>,
+    friction: f32,
+}
+
+impl Collider {
+    /// A single wall segment.
+    fn wall(a: Vec2, b: Vec2, friction: f32) -> Self {
+        Self {
+            segments: vec![Segment { a, b }],
+            friction,
+        }
+    }
+
+    /// A closed polygon from a point loop (last point joins back to the first).
+    fn polygon(points: &[Vec2], friction: f32) -> Self {
+        let segments = (0..points.len())
+            .map(|i| Segment {
+                a: points[i],
+                b: points[(i + 1) % points.len()],
+            })
+            .collect();
+        Self { segments, friction }
+    }
+}
+
+/// All scene colliders plus a segment-bucket broad phase keyed on the same
+/// `H`-sized cells as the particle grid, so resolving a move only tests the
+/// handful of segments near it rather than every collider.
+#[derive(Default)]
+struct ColliderSet {
+    colliders: Vec<Collider>,
+    buckets: HashMap<(i32, i32), Vec<(usize, usize)>>,
+}
+
+impl ColliderSet {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, collider: Collider) {
+        self.colliders.push(collider);
+        self.rebuild();
+    }
+
+    /// Rasterize every segment's AABB into the cells it overlaps.
+    fn rebuild(&mut self) {
+        self.buckets.clear();
+        for (ci, collider) in self.colliders.iter().enumerate() {
+            for (si, seg) in collider.segments.iter().enumerate() {
+                let (min, max) = (seg.a.min(seg.b), seg.a.max(seg.b));
+                let (x0, y0) = Grid::cell(min);
+                let (x1, y1) = Grid::cell(max);
+                for gx in x0..=x1 {
+                    for gy in y0..=y1 {
+                        self.buckets.entry((gx, gy)).or_default().push((ci, si));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolve a proposed move `x_old -> x_new` with velocity `v` against the
+    /// nearest collider it crosses, returning the corrected position/velocity.
+    /// The normal component is reflected with `BOUND_DAMPING`; the tangential
+    /// component is kept, scaled by `1 - friction` of the collider.
+    fn resolve(&self, x_old: Vec2, x_new: Vec2, v: Vec2) -> (Vec2, Vec2) {
+        if self.buckets.is_empty() {
+            return (x_new, v);
+        }
+        let (c0, c1) = (Grid::cell(x_old.min(x_new)), Grid::cell(x_old.max(x_new)));
+        let mut best: Option<(f32, usize, usize)> = None;
+        for gx in c0.0..=c1.0 {
+            for gy in c0.1..=c1.1 {
+                let Some(bucket) = self.buckets.get(&(gx, gy)) else {
+                    continue;
+                };
+                for &(ci, si) in bucket {
+                    if let Some(t) = self.colliders[ci].segments[si].cross(x_old, x_new) {
+                        if best.map_or(true, |(bt, _, _)| t < bt) {
+                            best = Some((t, ci, si));
+                        }
+                    }
+                }
+            }
+        }
+        let Some((t, ci, si)) = best else {
+            return (x_new, v);
+        };
+        let collider = &self.colliders[ci];
+        let seg = &collider.segments[si];
+        let mut n = seg.normal();
+        if n.dot(x_new - x_old) > 0.0 {
+            n = -n;
+        }
+        let hit = x_old + (x_new - x_old) * t;
+        let v_n = v.dot(n) * n;
+        let v_t = v - v_n;
+        let v_new = BOUND_DAMPING * v_n + (1.0 - collider.friction) * v_t;
+        (hit + n * EPS * 0.1, v_new)
+    }
+}
+
+/// How a seed region lays its particles out.
+#[derive(Debug, Clone, Copy)]
+enum Distribution {
+    /// Exact lattice at `spacing`.
+    Grid,
+    /// Lattice plus an independent random offset of up to `amount` per axis.
+    Jittered(f32),
+    /// Rejection-sampled points kept at least `H` apart (Poisson-ish).
+    Random,
+}
+
+/// A rectangular block of fluid to seed at startup.
+#[derive(Debug, Clone, Copy)]
+struct SeedRegion {
+    min: Vec2,
+    max: Vec2,
+    spacing: f32,
+    distribution: Distribution,
+}
+
+impl SeedRegion {
+    /// Append this region's particles to `particles`, never exceeding `cap`.
+    fn fill(&self, particles: &mut Vec<Particle>, cap: usize) {
+        match self.distribution {
+            Distribution::Grid => {
+                let mut y = self.min.y;
+                while y <= self.max.y {
+                    let mut x = self.min.x;
+                    while x <= self.max.x {
+                        if particles.len() >= cap {
+                            return;
+                        }
+                        particles.push(Particle::new(x, y));
+                        x += self.spacing;
+                    }
+                    y += self.spacing;
+                }
+            }
+            Distribution::Jittered(amount) => {
+                let mut y = self.min.y;
+                while y <= self.max.y {
+                    let mut x = self.min.x;
+                    while x <= self.max.x {
+                        if particles.len() >= cap {
+                            return;
+                        }
+                        let jx: f32 = rand::gen_range(0.0, amount);
+                        let jy: f32 = rand::gen_range(0.0, amount);
+                        particles.push(Particle::new(x + jx, y + jy));
+                        x += self.spacing;
+                    }
+                    y += self.spacing;
+                }
+            }
+            Distribution::Random => {
+                let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+                let target = (area / (self.spacing * self.spacing)) as usize;
+                let start = particles.len();
+                let mut tries = 0;
+                while particles.len() - start < target && tries < target * 40 {
+                    tries += 1;
+                    if particles.len() >= cap {
+                        break;
+                    }
+                    let c = Vec2::new(
+                        rand::gen_range(self.min.x, self.max.x),
+                        rand::gen_range(self.min.y, self.max.y),
+                    );
+                    if particles[start..].iter().any(|p| p.x.distance(c) < H) {
+                        continue;
+                    }
+                    particles.push(Particle::new(c.x, c.y));
+                }
+            }
+        }
+    }
+}
+
+/// A faucet that spawns a row of particles at a fixed position and velocity
+/// every `interval` steps, until it has emitted `cap` of them.
+#[derive(Debug, Clone, Copy)]
+struct Emitter {
+    origin: Vec2,
+    velocity: Vec2,
+    width: f32,
+    spacing: f32,
+    interval: usize,
+    cap: usize,
+}
+
+/// A full authorable scene: the blocks seeded at startup and the emitters that
+/// keep adding particles while the sim runs.
+#[derive(Debug, Clone, Default)]
+struct Scene {
+    regions: Vec<SeedRegion>,
+    emitters: Vec<Emitter>,
+    colliders: Vec<Collider>,
+}
+
+impl Scene {
+    /// The classic jittered dam block, now with a ramp the collapsing column
+    /// slides down and a triangular block it has to part around.
+    fn dam_break() -> Self {
+        Self {
+            regions: vec![SeedRegion {
+                min: Vec2::new(VIEW_WIDTH / 7.0, EPS),
+                max: Vec2::new(VIEW_WIDTH / 2.0, VIEW_HEIGHT - EPS * 2.0),
+                spacing: H,
+                distribution: Distribution::Jittered(1.0),
+            }],
+            emitters: vec![],
+            colliders: vec![
+                Collider::wall(
+                    Vec2::new(VIEW_WIDTH * 0.5, VIEW_HEIGHT * 0.35),
+                    Vec2::new(VIEW_WIDTH * 0.95, 0.0),
+                    0.0,
+                ),
+                Collider::polygon(
+                    &[
+                        Vec2::new(VIEW_WIDTH * 0.6, 0.0),
+                        Vec2::new(VIEW_WIDTH * 0.72, VIEW_HEIGHT * 0.18),
+                        Vec2::new(VIEW_WIDTH * 0.84, 0.0),
+                    ],
+                    0.0,
+                ),
+            ],
+        }
+    }
+}
+
+/// Read a little-endian `u32` at `off`, returning an error (not a panic) if the
+/// buffer is too short.
+fn read_u32(buf: &[u8], off: usize) -> io::Result<u32> {
+    let bytes = buf
+        .get(off..off + 4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated point cache"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read a little-endian `f32` at `off`, returning an error if the buffer is too
+/// short.
+fn read_f32(buf: &[u8], off: usize) -> io::Result<f32> {
+    let bytes = buf
+        .get(off..off + 4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated point cache"))?;
+    Ok(f32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A baked point cache: per-frame particle positions, mirroring Blender's
+/// PTCACHE. The on-disk layout is a little-endian `u32` frame count, then for
+/// each frame a `u32` particle count followed by that many `(f32, f32)`
+/// positions. The per-frame count lets emitters grow the particle set mid-bake
+/// without desyncing playback.
+struct PointCache {
+    frames: Vec<Vec<Vec2>>,
+}
+
+impl PointCache {
+    /// Run `sim` headlessly for `frames` steps, recording positions each frame.
+    fn bake(sim: &mut Sim, frames: usize) -> Self {
+        let mut out = Vec::with_capacity(frames);
+        for _ in 0..frames {
+            sim.advance();
+            out.push(sim.particles.iter().map(|p| p.x).collect());
+        }
+        Self { frames: out }
+    }
+
+    fn write(&self, path: &str) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        f.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+        for frame in &self.frames {
+            f.write_all(&(frame.len() as u32).to_le_bytes())?;
+            for p in frame {
+                f.write_all(&p.x.to_le_bytes())?;
+                f.write_all(&p.y.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(path: &str) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let frame_count = read_u32(&buf, 0)? as usize;
+        let mut frames = Vec::with_capacity(frame_count);
+        let mut off = 4;
+        for _ in 0..frame_count {
+            let count = read_u32(&buf, off)? as usize;
+            off += 4;
+            let mut frame = Vec::with_capacity(count);
+            for _ in 0..count {
+                let x = read_f32(&buf, off)?;
+                let y = read_f32(&buf, off + 4)?;
+                off += 8;
+                frame.push(Vec2::new(x, y));
+            }
+            frames.push(frame);
+        }
+        Ok(Self { frames })
+    }
+
+    /// Index of the last cached frame.
+    fn last_frame(&self) -> f32 {
+        self.frames.len().saturating_sub(1) as f32
+    }
+
+    /// Positions at a (possibly fractional) playback frame, linearly
+    /// interpolated between the two bracketing cached frames. An integer frame
+    /// returns that frame's positions as stored. Where the two frames differ in
+    /// count (an emitter added particles), the overlap is interpolated and any
+    /// extra particles are taken from the later frame.
+    fn sample(&self, time: f32) -> Vec<Vec2> {
+        if self.frames.is_empty() {
+            return vec![];
+        }
+        let last = self.frames.len() - 1;
+        let time = time.clamp(0.0, last as f32);
+        let f0 = time.floor() as usize;
+        let f1 = (f0 + 1).min(last);
+        let alpha = time - f0 as f32;
+        if alpha == 0.0 {
+            return self.frames[f0].clone();
+        }
+        let (a, b) = (&self.frames[f0], &self.frames[f1]);
+        let n = a.len().min(b.len());
+        let mut out: Vec<Vec2> = a[..n]
+            .iter()
+            .zip(&b[..n])
+            .map(|(a, b)| a.lerp(*b, alpha))
+            .collect();
+        out.extend_from_slice(&b[n..]);
+        out
+    }
+}
+
 struct Sim {
     particles: Vec<Particle>,
+    grid: Grid,
+    colliders: ColliderSet,
+    solver: Solver,
+    parallel: bool,
+    scene: Scene,
+    emitted: Vec<usize>,
+    step_count: usize,
+    scratch: Vec<Particle>,
 }
 
 impl Sim {
     fn new() -> Self {
-        Self { particles: vec![] }
+        Self {
+            particles: vec![],
+            grid: Grid::new(),
+            colliders: ColliderSet::new(),
+            solver: Solver::Sph,
+            parallel: true,
+            scene: Scene::dam_break(),
+            emitted: vec![],
+            step_count: 0,
+            scratch: vec![],
+        }
     }
 
     fn clear(&mut self) {
@@ -52,28 +532,64 @@ impl Sim {
     }
 
     fn init(&mut self) {
-        let mut y = EPS;
-        while y < VIEW_HEIGHT - EPS * 2.0 {
-            let mut x = VIEW_WIDTH / 7.0;
-            while x <= VIEW_WIDTH / 2.0 {
-                if self.particles.len() < DAM_PARTICLES {
-                    let jitter: f32 = rand::gen_range(0.0, 1.0);
-                    self.particles.push(Particle::new(x + jitter, y));
-                } else {
-                    return;
+        self.step_count = 0;
+        self.emitted = vec![0; self.scene.emitters.len()];
+        for region in &self.scene.regions {
+            region.fill(&mut self.particles, DAM_PARTICLES);
+        }
+        self.colliders = ColliderSet::new();
+        for collider in self.scene.colliders.clone() {
+            self.colliders.push(collider);
+        }
+    }
+
+    /// Run the scene's emitters for the current step, appending any newly
+    /// spawned particles.
+    fn emit(&mut self) {
+        let Sim {
+            particles,
+            scene,
+            emitted,
+            step_count,
+            ..
+        } = self;
+        for (i, e) in scene.emitters.iter().enumerate() {
+            // `interval == 0` means "every step"; it must not reach the modulo.
+            let due = e.interval == 0 || *step_count % e.interval == 0;
+            if due && emitted[i] < e.cap {
+                let mut off = 0.0;
+                while off <= e.width && emitted[i] < e.cap {
+                    if particles.len() >= DAM_PARTICLES {
+                        break;
+                    }
+                    let mut p = Particle::new(e.origin.x + off, e.origin.y);
+                    p.v = e.velocity;
+                    particles.push(p);
+                    emitted[i] += 1;
+                    off += e.spacing;
                 }
-                x += H;
             }
-            y += H;
         }
+        *step_count += 1;
+    }
+
+    /// Refresh the reusable particle snapshot and rebuild the spatial grid from
+    /// it. Call once per substep; the density and force passes then share the
+    /// same snapshot and buckets (positions don't change between them).
+    fn rebuild_grid(&mut self) {
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&self.particles);
+        self.grid.rebuild(&self.scratch);
     }
 
     fn compute_density_pressure(&mut self) {
         let poly_6: f32 = 4.0 / (PI * H.powf(8.0));
-        let x = self.particles.clone();
-        self.particles.iter_mut().for_each(|pi| {
+        let x = &self.scratch;
+        let grid = &self.grid;
+        let kernel = |pi: &mut Particle| {
             pi.rho = 0.0;
-            x.iter().for_each(|pj| {
+            grid.neighbors(pi.x).for_each(|j| {
+                let pj = &x[j];
                 let rij = pj.x - pi.x;
                 let r2: f32 = rij.dot(rij);
                 if r2 < HSQ {
@@ -81,17 +597,30 @@ impl Sim {
                 }
             });
             pi.p = GAS * (pi.rho - REST_DENS);
-        });
+        };
+        if self.parallel {
+            self.particles.par_iter_mut().for_each(&kernel);
+        } else {
+            self.particles.iter_mut().for_each(&kernel);
+        }
     }
 
     fn compute_forces(&mut self) {
         let spiky_grad: f32 = -10.0 / (PI * H.powf(5.0));
         let visc_lap: f32 = 40.0 / (PI * H.powf(5.0));
-        let x = self.particles.clone();
-        self.particles.iter_mut().for_each(|pi| {
+        // The density pass wrote fresh rho/p into the live particles; mirror
+        // those into the shared snapshot so neighbor reads see them.
+        for (s, p) in self.scratch.iter_mut().zip(self.particles.iter()) {
+            s.rho = p.rho;
+            s.p = p.p;
+        }
+        let x = &self.scratch;
+        let grid = &self.grid;
+        let kernel = |pi: &mut Particle| {
             let mut fpress: Vec2 = Vec2::ZERO;
             let mut fvisc: Vec2 = Vec2::ZERO;
-            x.iter().for_each(|pj| {
+            grid.neighbors(pi.x).for_each(|j| {
+                let pj = &x[j];
                 if pi != pj {
                     let rij = pj.x - pi.x;
                     let r: f32 = rij.dot(rij).sqrt();
@@ -105,13 +634,43 @@ impl Sim {
             });
             let fgrav = G * MASS / pi.rho;
             pi.f = fpress + fvisc + fgrav;
-        });
+        };
+        if self.parallel {
+            self.particles.par_iter_mut().for_each(&kernel);
+        } else {
+            self.particles.iter_mut().for_each(&kernel);
+        }
     }
 
-    fn integrate(&mut self) {
-        self.particles.iter_mut().for_each(|p| {
-            p.v += DT * p.f / p.rho;
-            p.x += DT * p.v;
+    /// Stable substep from the CFL and force conditions, using the speed of
+    /// sound estimate `c = sqrt(GAS)`. Must be called after `compute_forces`.
+    fn stable_timestep(&self) -> f32 {
+        let c = GAS.sqrt();
+        let mut v_max: f32 = 0.0;
+        let mut f_max: f32 = 0.0;
+        for p in &self.particles {
+            v_max = v_max.max(p.v.length());
+            f_max = f_max.max((p.f / p.rho).length());
+        }
+        let dt_cfl = LAMBDA_V * H / (c + v_max);
+        let dt_force = if f_max > 0.0 {
+            LAMBDA_F * (H / f_max).sqrt()
+        } else {
+            DT_MAX
+        };
+        dt_cfl.min(dt_force).clamp(DT_MIN, DT_MAX)
+    }
+
+    fn integrate(&mut self, dt: f32) {
+        let colliders = &self.colliders;
+        let kernel = |p: &mut Particle| {
+            p.v += dt * p.f / p.rho;
+            let x_old = p.x;
+            p.x += dt * p.v;
+
+            let (x_new, v_new) = colliders.resolve(x_old, p.x, p.v);
+            p.x = x_new;
+            p.v = v_new;
 
             if p.x.x - EPS < 0.0 {
                 p.v.x *= BOUND_DAMPING;
@@ -129,14 +688,176 @@ impl Sim {
                 p.v.y *= BOUND_DAMPING;
                 p.x.y = VIEW_HEIGHT - EPS;
             }
-        });
+        };
+        if self.parallel {
+            self.particles.par_iter_mut().for_each(&kernel);
+        } else {
+            self.particles.iter_mut().for_each(&kernel);
+        }
+    }
+
+    /// One full step of the Clavet double-density relaxation solver.
+    ///
+    /// Unlike the SPH path this works directly on positions: gravity and
+    /// viscosity are applied to velocities, positions are advanced, a
+    /// relaxation pass pushes overlapping particles apart (making the fluid
+    /// nearly incompressible even at large `dt`), and velocities are finally
+    /// recovered as `(x - x_prev)/dt`.
+    fn step_clavet(&mut self, fp: &FluidParams, dt: f32) {
+        self.particles.iter_mut().for_each(|p| p.v += dt * G);
+        self.apply_viscosity(fp, dt);
+
+        let prev: Vec<Vec2> = self.particles.iter().map(|p| p.x).collect();
+        self.particles.iter_mut().for_each(|p| p.x += dt * p.v);
+
+        self.double_density_relaxation(fp, dt);
+
+        // Resolve the whole x_prev -> x move against the scene colliders (same
+        // broad phase the SPH path uses), clamp to the box, then recover the
+        // velocity from the corrected positions so collisions are respected.
+        let colliders = &self.colliders;
+        self.particles
+            .iter_mut()
+            .zip(prev.iter())
+            .for_each(|(p, &x_prev)| {
+                let (x_new, _) = colliders.resolve(x_prev, p.x, p.v);
+                p.x = x_new;
+                if p.x.x - EPS < 0.0 {
+                    p.x.x = EPS;
+                }
+                if p.x.x + EPS > VIEW_WIDTH {
+                    p.x.x = VIEW_WIDTH - EPS;
+                }
+                if p.x.y - EPS < 0.0 {
+                    p.x.y = EPS;
+                }
+                if p.x.y + EPS > VIEW_HEIGHT {
+                    p.x.y = VIEW_HEIGHT - EPS;
+                }
+                p.v = (p.x - x_prev) / dt;
+            });
+    }
+
+    /// Linear + quadratic viscosity applied as symmetric velocity impulses
+    /// along `r_hat`, scaled by the inward radial velocity `u`.
+    fn apply_viscosity(&mut self, fp: &FluidParams, dt: f32) {
+        if fp.sigma == 0.0 && fp.beta == 0.0 {
+            return;
+        }
+        let snapshot = self.particles.clone();
+        self.grid.rebuild(&snapshot);
+        let mut impulses = vec![Vec2::ZERO; self.particles.len()];
+        for (i, pi) in snapshot.iter().enumerate() {
+            for j in self.grid.neighbors(pi.x) {
+                if j <= i {
+                    continue;
+                }
+                let pj = &snapshot[j];
+                let rij = pj.x - pi.x;
+                let r = rij.length();
+                if r >= H || r == 0.0 {
+                    continue;
+                }
+                let rhat = rij / r;
+                let q = r / H;
+                let u = (pi.v - pj.v).dot(rhat);
+                if u <= 0.0 {
+                    continue;
+                }
+                let imp = dt * (1.0 - q) * (fp.sigma * u + fp.beta * u * u) * rhat;
+                impulses[i] -= imp / 2.0;
+                impulses[j] += imp / 2.0;
+            }
+        }
+        self.particles
+            .iter_mut()
+            .zip(impulses)
+            .for_each(|(p, imp)| p.v += imp);
+    }
+
+    /// Double-density relaxation: accumulate density and near-density in the
+    /// `(1-q)` metric, turn them into pressure and near-pressure, and displace
+    /// neighbor pairs apart along `r_hat`.
+    fn double_density_relaxation(&mut self, fp: &FluidParams, dt: f32) {
+        let snapshot = self.particles.clone();
+        self.grid.rebuild(&snapshot);
+        let dt2 = dt * dt;
+        let mut deltas = vec![Vec2::ZERO; self.particles.len()];
+        for (i, pi) in snapshot.iter().enumerate() {
+            let mut rho = 0.0;
+            let mut rho_near = 0.0;
+            for j in self.grid.neighbors(pi.x) {
+                if j == i {
+                    continue;
+                }
+                let r = (snapshot[j].x - pi.x).length();
+                if r >= H {
+                    continue;
+                }
+                let q = 1.0 - r / H;
+                rho += q * q;
+                rho_near += q * q * q;
+            }
+            let pressure = fp.k * (rho - fp.rho0);
+            let pressure_near = fp.k_near * rho_near;
+            for j in self.grid.neighbors(pi.x) {
+                if j == i {
+                    continue;
+                }
+                let rij = snapshot[j].x - pi.x;
+                let r = rij.length();
+                if r >= H || r == 0.0 {
+                    continue;
+                }
+                let rhat = rij / r;
+                let q = 1.0 - r / H;
+                let d =
+                    dt2 * (pressure * q + pressure_near * q * q) * rhat;
+                deltas[j] += d / 2.0;
+                deltas[i] -= d / 2.0;
+            }
+        }
+        self.particles
+            .iter_mut()
+            .zip(deltas)
+            .for_each(|(p, d)| p.x += d);
+    }
+
+    /// Advance the simulation by one rendered frame according to the active
+    /// solver.
+    fn advance(&mut self) {
+        self.emit();
+        match self.solver {
+            Solver::Sph => {
+                let mut elapsed = 0.0;
+                while elapsed < FRAME_BUDGET {
+                    self.rebuild_grid();
+                    self.compute_density_pressure();
+                    self.compute_forces();
+                    let dt = self.stable_timestep().min(FRAME_BUDGET - elapsed);
+                    self.integrate(dt);
+                    elapsed += dt;
+                }
+            }
+            Solver::Clavet(fp) => {
+                self.step_clavet(&fp, FRAME_BUDGET);
+            }
+        }
     }
 }
 
+/// How the viewer is sourcing particle positions: stepping the sim live, or
+/// replaying a baked point cache.
+enum Mode {
+    Live,
+    Playback { cache: PointCache, time: f32 },
+}
+
 #[macroquad::main("SPH")]
 async fn main() {
     let mut sim = Sim::new();
     sim.init();
+    let mut mode = Mode::Live;
 
     loop {
         if is_key_pressed(KeyCode::Escape) {
@@ -145,35 +866,188 @@ async fn main() {
         if is_key_pressed(KeyCode::R) {
             sim.clear();
             sim.init();
+            mode = Mode::Live;
         }
-
-        for _ in 0..UPDATES_PER_FRAME {
-            sim.compute_density_pressure();
-            sim.compute_forces();
-            sim.integrate();
+        if is_key_pressed(KeyCode::T) {
+            sim.parallel = !sim.parallel;
+        }
+        if is_key_pressed(KeyCode::S) {
+            sim.solver = match sim.solver {
+                Solver::Sph => Solver::Clavet(FluidParams::default()),
+                Solver::Clavet(_) => Solver::Sph,
+            };
+        }
+        // Bake the current scene to disk, then switch to replaying it.
+        if is_key_pressed(KeyCode::B) {
+            let cache = PointCache::bake(&mut sim, BAKE_FRAMES);
+            let _ = cache.write(CACHE_PATH);
+            sim.clear();
+            sim.init();
+            mode = Mode::Playback { cache, time: 0.0 };
+        }
+        // Load the cache from disk and replay it.
+        if is_key_pressed(KeyCode::P) {
+            if let Ok(cache) = PointCache::read(CACHE_PATH) {
+                mode = Mode::Playback { cache, time: 0.0 };
+            }
+        }
+        // Drop back to the live solver.
+        if is_key_pressed(KeyCode::L) {
+            mode = Mode::Live;
         }
 
+        let points: Vec<Vec2> = match &mut mode {
+            Mode::Live => {
+                sim.advance();
+                sim.particles.iter().map(|p| p.x).collect()
+            }
+            Mode::Playback { cache, time } => {
+                // Scrub with the arrow keys, otherwise play forward one frame.
+                if is_key_down(KeyCode::Left) {
+                    *time -= SCRUB_STEP;
+                } else if is_key_down(KeyCode::Right) {
+                    *time += SCRUB_STEP;
+                } else {
+                    *time += 1.0;
+                }
+                *time = time.clamp(0.0, cache.last_frame());
+                cache.sample(*time)
+            }
+        };
+
         let i = screen_width() / VIEW_WIDTH;
         let j = screen_height() / VIEW_HEIGHT;
 
         clear_background(GRAY);
         draw_text(&format!("{} FPS", get_fps()), 10.0, 30.0, 40.0, YELLOW);
         draw_text(
-            &format!("{} PARTICLES", sim.particles.len()),
+            &format!("{} PARTICLES", points.len()),
             10.0,
             60.0,
             40.0,
             YELLOW,
         );
-        sim.particles.iter().for_each(|p| {
+        if let Mode::Playback { cache, time } = &mode {
+            draw_text(
+                &format!("PLAYBACK {:.0}/{:.0}", time, cache.last_frame()),
+                10.0,
+                90.0,
+                40.0,
+                YELLOW,
+            );
+        }
+        points.iter().for_each(|x| {
             draw_circle(
-                p.x.x * i,
-                screen_height() - p.x.y * j,
+                x.x * i,
+                screen_height() - x.y * j,
                 4.0 * if i > j { i } else { j },
                 BLUE,
             );
         });
+        for collider in &sim.colliders.colliders {
+            for seg in &collider.segments {
+                draw_line(
+                    seg.a.x * i,
+                    screen_height() - seg.a.y * j,
+                    seg.b.x * i,
+                    screen_height() - seg.b.y * j,
+                    2.0,
+                    DARKGRAY,
+                );
+            }
+        }
 
         next_frame().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_neighbors_cover_adjacent_cells_only() {
+        // H == 16, so cell size is 16.
+        let particles = vec![
+            Particle::new(8.0, 8.0),   // 0: cell (0, 0)
+            Particle::new(20.0, 8.0),  // 1: cell (1, 0) - adjacent
+            Particle::new(8.0, 40.0),  // 2: cell (0, 2) - two cells up, excluded
+        ];
+        let mut grid = Grid::new();
+        grid.rebuild(&particles);
+        let found: std::collections::HashSet<usize> =
+            grid.neighbors(particles[0].x).collect();
+        assert!(found.contains(&0));
+        assert!(found.contains(&1));
+        assert!(!found.contains(&2));
+    }
+
+    #[test]
+    fn segment_cross_reports_intersection_parameter() {
+        // Vertical wall at x == 0 spanning y in [-1, 1].
+        let seg = Segment {
+            a: Vec2::new(0.0, -1.0),
+            b: Vec2::new(0.0, 1.0),
+        };
+        // Move straight across the wall: crosses at the midpoint.
+        let t = seg.cross(Vec2::new(-1.0, 0.0), Vec2::new(1.0, 0.0));
+        assert!((t.unwrap() - 0.5).abs() < 1e-6);
+        // A move that passes above the wall never touches it.
+        assert!(seg.cross(Vec2::new(-1.0, 2.0), Vec2::new(1.0, 2.0)).is_none());
+    }
+
+    #[test]
+    fn near_density_relaxation_pushes_neighbors_apart() {
+        // Pure near-pressure (k == 0) is always repulsive, so two close
+        // particles must end up further apart after a relaxation pass.
+        let mut sim = Sim::new();
+        sim.particles = vec![Particle::new(100.0, 100.0), Particle::new(100.0, 102.0)];
+        let fp = FluidParams {
+            k: 0.0,
+            k_near: 1.0,
+            rho0: 0.0,
+            sigma: 0.0,
+            beta: 0.0,
+        };
+        let before = sim.particles[0].x.distance(sim.particles[1].x);
+        sim.double_density_relaxation(&fp, 1.0);
+        let after = sim.particles[0].x.distance(sim.particles[1].x);
+        assert!(after > before, "expected {after} > {before}");
+    }
+
+    #[test]
+    fn point_cache_round_trips_through_disk() {
+        let cache = PointCache {
+            frames: vec![
+                vec![Vec2::new(1.0, 2.0), Vec2::new(3.0, 4.0)],
+                // A later frame with an extra (emitted) particle.
+                vec![Vec2::new(5.0, 6.0), Vec2::new(7.0, 8.0), Vec2::new(9.0, 10.0)],
+            ],
+        };
+        let path = std::env::temp_dir().join("sph_point_cache_roundtrip.ptc");
+        let path = path.to_str().unwrap();
+        cache.write(path).unwrap();
+        let read = PointCache::read(path).unwrap();
+        assert_eq!(read.frames, cache.frames);
+
+        // Integer frames return the stored positions verbatim.
+        assert_eq!(read.sample(0.0), cache.frames[0]);
+        // Halfway interpolates the overlap and keeps the extra tail particle.
+        let mid = read.sample(0.5);
+        assert_eq!(mid.len(), 3);
+        assert!(mid[0].distance(Vec2::new(3.0, 4.0)) < 1e-6);
+        assert!(mid[2].distance(Vec2::new(9.0, 10.0)) < 1e-6);
+    }
+
+    #[test]
+    fn read_rejects_truncated_cache() {
+        let path = std::env::temp_dir().join("sph_point_cache_truncated.ptc");
+        let path = path.to_str().unwrap();
+        // Claims one frame of two particles but supplies no payload.
+        let mut f = File::create(path).unwrap();
+        f.write_all(&1u32.to_le_bytes()).unwrap();
+        f.write_all(&2u32.to_le_bytes()).unwrap();
+        drop(f);
+        assert!(PointCache::read(path).is_err());
+    }
+}